@@ -4,34 +4,62 @@
 // This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2022 Datadog, Inc.
 //
 
-use nix::sys;
 use std::{
     fmt,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
     thread::JoinHandle,
     time::{Duration, Instant},
 };
 use crate::executor::TaskQueueHandle;
 
+mod clock;
+mod stream;
+
+#[cfg(test)]
+pub(crate) use clock::PausedClock;
+pub(crate) use clock::{Clock, MonotonicClock};
+pub use stream::{OwnedStallDetection, StreamStallDetectionHandler};
+
 pub struct StallDetection<'a> {
     executor: usize,
     queue_handle: TaskQueueHandle,
     queue_name: &'a str,
-    trace: backtrace::Backtrace,
+    /// A single consolidated call stack built from every frame captured
+    /// while the task queue was over budget, paired with how many times the
+    /// signal fired. The signal handler streams frames one at a time rather
+    /// than one backtrace per fire, so there's no way to attribute a frame
+    /// to a particular fire -- this is always a single entry, even when
+    /// [`StallDetectionHandler::sampling_period`] causes multiple fires.
+    samples: Vec<(backtrace::Backtrace, usize)>,
+    sample_count: usize,
     budget: Duration,
     overage: Duration,
 }
 
+impl StallDetection<'_> {
+    /// The consolidated call stack captured while over budget, paired with
+    /// how many times the signal fired. Empty if no frames were captured.
+    pub fn samples(&self) -> &[(backtrace::Backtrace, usize)] {
+        &self.samples
+    }
+
+    /// How many times the signal fired while this task queue was stalled.
+    pub fn sample_count(&self) -> usize {
+        self.sample_count
+    }
+}
+
 impl fmt::Debug for StallDetection<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("StallDetection")
             .field("executor", &self.executor)
             .field("queue_handle", &self.queue_handle)
             .field("queue_name", &self.queue_name)
-            .field("trace", &self.trace)
+            .field("samples", &self.samples)
+            .field("sample_count", &self.sample_count)
             .field("budget", &self.budget)
             .field("overage", &self.overage)
             .finish()
@@ -43,9 +71,18 @@ impl fmt::Display for StallDetection<'_> {
         write!(
             f,
             "[stall-detector -- executor {}] task queue {} went over-budget: {:#?} (budget: \
-             {:#?}). Backtrace: {:#?}",
-            self.executor, self.queue_name, self.overage, self.budget, self.trace,
-        )
+             {:#?}). {} sample(s) across {} distinct call stack(s):",
+            self.executor,
+            self.queue_name,
+            self.overage,
+            self.budget,
+            self.sample_count,
+            self.samples.len(),
+        )?;
+        for (trace, hits) in &self.samples {
+            write!(f, "\n-- {hits} hit(s) --\n{trace:#?}")?;
+        }
+        Ok(())
     }
 }
 
@@ -60,10 +97,37 @@ pub trait StallDetectionHandler: std::fmt::Debug + Send + Sync {
     /// What signal number to use; see values in libc::SIG*
     fn signal(&self) -> u8;
 
+    /// How often to keep re-sampling a backtrace once a task queue has
+    /// gone over budget. Returning `Some(period)` turns the detector into
+    /// a statistical profiler: rather than capturing a single backtrace at
+    /// the high-water mark, it keeps sampling every `period` for as long
+    /// as the stall continues. The captured frames are consolidated into
+    /// a single [`StallDetection::samples`] entry paired with
+    /// [`StallDetection::sample_count`], the number of times the signal
+    /// fired. The default of `None` preserves the original one-shot
+    /// behavior (a single sample at the high-water mark).
+    fn sampling_period(&self, _queue_handle: TaskQueueHandle, _max_expected_runtime: Duration) -> Option<Duration> {
+        None
+    }
+
     /// Handler called when a task exceeds its budget
     fn stall(&self, detection: StallDetection<'_>);
 }
 
+// We consider a queue to be stalling the system if it failed to yield in due
+// time. For a given maximum expected runtime, we allow a margin of error f 10%
+// (and an absolute minimum of 10ms) after which we record a stacktrace. i.e. a
+// task queue has should return shortly after `need_preempt()` returns
+// true or the stall detector triggers. For example::
+// * If a task queue has a preempt timer of 100ms the the stall detector
+// triggers if it doesn't yield after running for 110ms.
+// * If a task queue has a preempt timer of 5ms the the stall detector
+// triggers if it doesn't yield after running for 15ms.
+pub(crate) fn default_high_water_mark(max_expected_runtime: Duration) -> Duration {
+    Duration::from_millis((max_expected_runtime.as_millis() as f64 * 0.1) as u64)
+        .max(Duration::from_millis(10))
+}
+
 /// Default settings for signal number, high water mark and stall handler.
 /// By default, the high water mark to consider a task queue stalled is set to
 /// 10% of the expected run time. The default handler will log a stack trace of the currently
@@ -75,17 +139,7 @@ impl StallDetectionHandler for DefaultStallDetectionHandler {
     /// The default high water mark is 10% of the preemption time,
     /// capped at 10ms.
     fn high_water_mark(&self, _queue_handle: TaskQueueHandle, max_expected_runtime: Duration) -> Option<Duration> {
-        // We consider a queue to be stalling the system if it failed to yield in due
-        // time. For a given maximum expected runtime, we allow a margin of error f 10%
-        // (and an absolute minimum of 10ms) after which we record a stacktrace. i.e. a
-        // task queue has should return shortly after `need_preempt()` returns
-        // true or the stall detector triggers. For example::
-        // * If a task queue has a preempt timer of 100ms the the stall detector
-        // triggers if it doesn't yield after running for 110ms.
-        // * If a task queue has a preempt timer of 5ms the the stall detector
-        // triggers if it doesn't yield after running for 15ms.
-        Some(Duration::from_millis((max_expected_runtime.as_millis() as f64 * 0.1) as u64)
-            .max(Duration::from_millis(10)))
+        Some(default_high_water_mark(max_expected_runtime))
     }
 
     /// The default signal is SIGUSR1.
@@ -101,11 +155,22 @@ impl StallDetectionHandler for DefaultStallDetectionHandler {
 
 #[derive(Debug)]
 pub(crate) struct StallDetector {
-    timer: Arc<sys::timerfd::TimerFd>,
+    clock: Arc<dyn Clock>,
     stall_handler: Box<dyn StallDetectionHandler + 'static>,
     timer_handler: Option<JoinHandle<()>>,
     id: usize,
     terminated: Arc<AtomicBool>,
+    // Some(period) for as long as the current task queue is being sampled
+    // (see StallDetectionHandler::sampling_period); cleared on disarm() so
+    // a subsequent, short task queue doesn't inherit it.
+    sampling: Arc<Mutex<Option<Duration>>>,
+    // How many times the signal has fired since the last disarm(). The
+    // signal handler that captures frames onto `tx`/`rx` below sends one
+    // frame at a time (not one Vec per fire, to keep that wire format
+    // stable for whatever installs the handler), so this is the only way
+    // to know how many distinct captures contributed to the frames
+    // eventually drained from `rx`.
+    fires: Arc<AtomicUsize>,
     // NOTE: we don't use signal_hook::low_level::channel as backtraces
     // have too many elements
     pub(crate) tx: crossbeam::channel::Sender<backtrace::BacktraceFrame>,
@@ -117,32 +182,49 @@ impl StallDetector {
         executor_id: usize,
         stall_handler: Box<dyn StallDetectionHandler + 'static>,
     ) -> std::io::Result<StallDetector> {
-        let timer = Arc::new(
-            sys::timerfd::TimerFd::new(
-                sys::timerfd::ClockId::CLOCK_MONOTONIC,
-                sys::timerfd::TimerFlags::empty(),
-            )
-            .map_err(std::io::Error::from)?,
-        );
+        Self::with_clock(executor_id, stall_handler, Arc::new(MonotonicClock::new()?))
+    }
+
+    /// Build a detector against an arbitrary [`Clock`], e.g. a
+    /// [`PausedClock`] so tests can drive stalls deterministically instead
+    /// of busy-looping real wall-clock time.
+    pub(crate) fn with_clock(
+        executor_id: usize,
+        stall_handler: Box<dyn StallDetectionHandler + 'static>,
+        clock: Arc<dyn Clock>,
+    ) -> std::io::Result<StallDetector> {
         let tid = unsafe { nix::libc::pthread_self() };
         let terminated = Arc::new(AtomicBool::new(false));
+        let sampling = Arc::new(Mutex::new(None));
+        let fires = Arc::new(AtomicUsize::new(0));
         let sig = stall_handler.signal();
-        let timer_handler = std::thread::spawn(enclose::enclose! { (terminated, timer) move || {
-            while timer.wait().is_ok() {
+        let timer_handler = std::thread::spawn(enclose::enclose! { (terminated, clock, sampling, fires) move || {
+            while clock.wait().is_ok() {
                 if terminated.load(Ordering::Relaxed) {
                     return
                 }
                 unsafe { nix::libc::pthread_kill(tid, sig.into()) };
+                fires.fetch_add(1, Ordering::Relaxed);
+                // The first fire is always the high-water-mark expiry; if the
+                // handler wants sampling, switch the clock over to a
+                // repeating fire at the sampling period so every subsequent
+                // `wait()` keeps delivering signals for as long as the task
+                // queue remains stalled.
+                if let Some(period) = *sampling.lock().unwrap() {
+                    clock.schedule_interval(period);
+                }
             }
         }});
         let (tx, rx) = crossbeam::channel::bounded(1 << 10);
 
         Ok(Self {
-            timer,
+            clock,
             timer_handler: Some(timer_handler),
             stall_handler,
             id: executor_id,
             terminated,
+            sampling,
+            fires,
             tx,
             rx,
         })
@@ -156,25 +238,32 @@ impl StallDetector {
         max_expected_runtime: Duration,
     ) -> Option<StallDetectorGuard<'_>> {
         self.stall_handler.high_water_mark(queue_handle, max_expected_runtime).map(|hwm| {
+            let sampling_period = self.stall_handler.sampling_period(queue_handle, max_expected_runtime);
             StallDetectorGuard::new(
                 self,
                 queue_handle,
                 queue_name,
                 start,
                 max_expected_runtime.saturating_add(hwm),
-            ).expect("Unable to create StallDetectorGuard, giving up")
+                sampling_period,
+            )
         })
     }
 
-    pub(crate) fn arm(&self, threshold: Duration) -> nix::Result<()> {
-        self.timer.set(
-            sys::timerfd::Expiration::OneShot(sys::time::TimeSpec::from(threshold)),
-            sys::timerfd::TimerSetTimeFlags::empty(),
-        )
+    pub(crate) fn arm(&self, threshold: Duration, sampling_period: Option<Duration>) {
+        *self.sampling.lock().unwrap() = sampling_period;
+        self.clock.schedule(threshold);
     }
 
-    pub(crate) fn disarm(&self) -> nix::Result<()> {
-        self.timer.unset()
+    pub(crate) fn disarm(&self) {
+        // Holding `sampling` across `cancel()` closes the race with the
+        // signal thread's read-then-reschedule in the loop above: either
+        // it observes the cleared `sampling` and doesn't re-arm, or it
+        // already re-armed and we cancel that right here, but it can never
+        // read a stale `Some(period)` and re-arm *after* we've cancelled.
+        let mut sampling = self.sampling.lock().unwrap();
+        self.clock.cancel();
+        *sampling = None;
     }
 }
 
@@ -182,16 +271,7 @@ impl Drop for StallDetector {
     fn drop(&mut self) {
         let timer_handler = self.timer_handler.take().unwrap();
         self.terminated.store(true, Ordering::Relaxed);
-
-        self.timer
-            .set(
-                sys::timerfd::Expiration::Interval(sys::time::TimeSpec::from(
-                    Duration::from_millis(1),
-                )),
-                sys::timerfd::TimerSetTimeFlags::empty(),
-            )
-            .expect("failed wake the timer for termination");
-
+        self.clock.wake();
         let _ = timer_handler.join();
     }
 }
@@ -211,45 +291,62 @@ impl<'detector> StallDetectorGuard<'detector> {
         queue_name: String,
         start: Instant,
         threshold: Duration,
-    ) -> nix::Result<Self> {
-        detector.arm(threshold).expect("Unable to arm stall detector, giving up");
-        Ok(Self {
+        sampling_period: Option<Duration>,
+    ) -> Self {
+        detector.arm(threshold, sampling_period);
+        Self {
             detector,
             queue_handle,
             queue_name,
             start,
             threshold,
-        })
+        }
     }
 }
 
 impl<'detector> Drop for StallDetectorGuard<'detector> {
     fn drop(&mut self) {
-        let _ = self.detector.disarm();
+        self.detector.disarm();
 
         let mut frames = vec![];
         while let Ok(frame) = self.detector.rx.try_recv() {
             frames.push(frame);
         }
-        let mut strace = backtrace::Backtrace::from(frames);
+        let fire_count = self.detector.fires.swap(0, Ordering::Relaxed);
 
-        if strace.frames().is_empty() {
+        if frames.is_empty() {
             return;
         }
 
-        let elapsed = self.start.elapsed();
-        strace.resolve();
+        let elapsed = self.detector.clock.now().saturating_duration_since(self.start);
         self.detector.stall_handler.stall(StallDetection {
             executor: self.detector.id,
             queue_name: &self.queue_name,
             queue_handle: self.queue_handle,
-            trace: strace,
+            samples: consolidate_samples(frames, fire_count),
+            sample_count: fire_count.max(1),
             budget: self.threshold,
             overage: elapsed.saturating_sub(self.threshold),
         });
     }
 }
 
+/// Build a single resolved [`backtrace::Backtrace`] from every frame drained
+/// off the channel since the last disarm. The signal handler streams frames
+/// one at a time rather than one capture per fire (see the note on
+/// `StallDetector`'s `fires` field), so there's no way to tell which frames belong to
+/// which of the `fire_count` signal deliveries -- rather than pretending
+/// otherwise, all of them are folded into one consolidated stack, paired
+/// with how many times the signal fired while the queue was stalled.
+fn consolidate_samples(
+    frames: Vec<backtrace::BacktraceFrame>,
+    fire_count: usize,
+) -> Vec<(backtrace::Backtrace, usize)> {
+    let mut trace = backtrace::Backtrace::from(frames);
+    trace.resolve();
+    vec![(trace, fire_count.max(1))]
+}
+
 #[cfg(test)]
 mod test {
     use crate::{
@@ -339,4 +436,67 @@ mod test {
                 ));
             });
     }
+
+    #[test]
+    fn stall_detector_overage_is_exact_with_paused_clock() {
+        use crate::executor::{
+            stall::{Clock, PausedClock, StallDetection, StallDetectionHandler, StallDetector},
+            TaskQueueHandle,
+        };
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Debug)]
+        struct CapturingHandler {
+            captured: Arc<Mutex<Option<(Duration, Duration)>>>,
+        }
+
+        impl StallDetectionHandler for CapturingHandler {
+            fn high_water_mark(
+                &self,
+                _queue_handle: TaskQueueHandle,
+                _max_expected_runtime: Duration,
+            ) -> Option<Duration> {
+                Some(Duration::from_millis(10))
+            }
+
+            fn signal(&self) -> u8 {
+                // SIGWINCH is ignored by default; this test never goes
+                // through the executor's real signal handler, so firing
+                // anything with a terminating default disposition would
+                // kill the test process.
+                nix::libc::SIGWINCH as u8
+            }
+
+            fn stall(&self, detection: StallDetection<'_>) {
+                *self.captured.lock().unwrap() = Some((detection.budget, detection.overage));
+            }
+        }
+
+        let captured = Arc::new(Mutex::new(None));
+        let clock = Arc::new(PausedClock::new());
+        let detector = StallDetector::with_clock(
+            0,
+            Box::new(CapturingHandler { captured: captured.clone() }),
+            clock.clone() as Arc<dyn Clock>,
+        )
+        .unwrap();
+
+        let queue_handle = TaskQueueHandle::new(0);
+        let start = clock.now();
+        let guard = detector
+            .enter_task_queue(queue_handle, "test".into(), start, Duration::ZERO)
+            .unwrap();
+
+        // Simulate a sample having been captured while stalled, then
+        // advance the virtual clock -- no real sleeping involved -- past
+        // the 10ms budget by exactly 15ms.
+        let frame = backtrace::Backtrace::new().frames()[0].clone();
+        detector.tx.send(frame).unwrap();
+        clock.advance(Duration::from_millis(25));
+        drop(guard);
+
+        let (budget, overage) = captured.lock().unwrap().take().unwrap();
+        assert_eq!(budget, Duration::from_millis(10));
+        assert_eq!(overage, Duration::from_millis(15));
+    }
 }
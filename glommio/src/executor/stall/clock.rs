@@ -0,0 +1,263 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2022 Datadog, Inc.
+//
+
+use nix::sys;
+use std::{
+    fmt,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Abstracts "what time is it now" and "fire me after N nanos" so the stall
+/// detector (and, eventually, the timer reactor) can be driven by a real OS
+/// clock in production and a paused/virtual one in tests. In the spirit of
+/// Tokio's `time::clock` module and embassy-time's `Driver` trait, a single
+/// trait object stands in for either source, letting
+/// [`super::StallDetector`] stay oblivious to which one it was built with.
+pub(crate) trait Clock: fmt::Debug + Send + Sync {
+    /// The current instant, as seen by this clock.
+    fn now(&self) -> Instant;
+
+    /// Arm a one-shot fire after `after` has elapsed, replacing any
+    /// previously scheduled fire.
+    fn schedule(&self, after: Duration);
+
+    /// Arm a repeating fire every `period`, replacing any previously
+    /// scheduled fire.
+    fn schedule_interval(&self, period: Duration);
+
+    /// Disarm a previously scheduled fire; a no-op if nothing is armed.
+    fn cancel(&self);
+
+    /// Unblock a thread currently parked in [`Self::wait`]. Used to wake the
+    /// stall detector's signal-delivery thread so it can notice it has been
+    /// asked to terminate. A previously scheduled fire is not preserved:
+    /// [`MonotonicClock`] replaces it with a 1ms repeating fire, and
+    /// `PausedClock` shuts down so that `wait` returns `Err` once any
+    /// pending fires have been drained.
+    fn wake(&self);
+
+    /// Block the calling thread until the next scheduled fire, or until
+    /// [`Self::wake`] is called. Returns `Err` only once this clock has
+    /// been permanently shut down and will never fire again.
+    fn wait(&self) -> std::io::Result<()>;
+}
+
+/// The production [`Clock`]: `CLOCK_MONOTONIC` via `timerfd`, so fires are
+/// delivered by the kernel even while the executor thread is off running
+/// (possibly stalled) user code.
+#[derive(Debug)]
+pub(crate) struct MonotonicClock {
+    timer: sys::timerfd::TimerFd,
+}
+
+impl MonotonicClock {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            timer: sys::timerfd::TimerFd::new(
+                sys::timerfd::ClockId::CLOCK_MONOTONIC,
+                sys::timerfd::TimerFlags::empty(),
+            )
+            .map_err(std::io::Error::from)?,
+        })
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn schedule(&self, after: Duration) {
+        self.timer
+            .set(
+                sys::timerfd::Expiration::OneShot(sys::time::TimeSpec::from(after)),
+                sys::timerfd::TimerSetTimeFlags::empty(),
+            )
+            .expect("Unable to arm stall detector clock, giving up");
+    }
+
+    fn schedule_interval(&self, period: Duration) {
+        self.timer
+            .set(
+                sys::timerfd::Expiration::Interval(sys::time::TimeSpec::from(period)),
+                sys::timerfd::TimerSetTimeFlags::empty(),
+            )
+            .expect("Unable to arm stall detector clock, giving up");
+    }
+
+    fn cancel(&self) {
+        let _ = self.timer.unset();
+    }
+
+    fn wake(&self) {
+        // A 1ms interval (rather than a one-shot) guarantees the waiting
+        // thread observes a fire even if it races with a concurrent
+        // `cancel`; it keeps firing harmlessly until the thread notices
+        // `terminated` and returns without re-arming anything.
+        self.timer
+            .set(
+                sys::timerfd::Expiration::Interval(sys::time::TimeSpec::from(Duration::from_millis(1))),
+                sys::timerfd::TimerSetTimeFlags::empty(),
+            )
+            .expect("failed to wake the stall detector clock");
+    }
+
+    fn wait(&self) -> std::io::Result<()> {
+        self.timer.wait().map(|_| ()).map_err(std::io::Error::from)
+    }
+}
+
+/// A paused/virtual [`Clock`] for tests: time only ever moves when a test
+/// calls [`PausedClock::advance`], so stall-detector assertions about exact
+/// over-budget amounts don't have to race real wall-clock sleeps.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct PausedClock {
+    state: Mutex<PausedClockState>,
+    condvar: Condvar,
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct PausedClockState {
+    base: Instant,
+    elapsed: Duration,
+    deadline: Option<Duration>,
+    interval: Option<Duration>,
+    pending_fires: usize,
+    terminated: bool,
+}
+
+#[cfg(test)]
+impl PausedClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Mutex::new(PausedClockState {
+                base: Instant::now(),
+                elapsed: Duration::ZERO,
+                deadline: None,
+                interval: None,
+                pending_fires: 0,
+                terminated: false,
+            }),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// Advance the virtual clock by `by`. If this crosses a scheduled
+    /// deadline, exactly one "expired" event is delivered to whoever is
+    /// parked in [`Clock::wait`] -- crossing several deadlines in one
+    /// `advance` (possible once an interval is armed) still only wakes the
+    /// waiter once per crossed deadline, never more, preserving in-order,
+    /// one-fire-per-deadline delivery.
+    pub(crate) fn advance(&self, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed += by;
+        while let Some(deadline) = state.deadline {
+            if state.elapsed < deadline {
+                break;
+            }
+            state.pending_fires += 1;
+            state.deadline = state.interval.map(|period| deadline + period);
+        }
+        self.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+impl Clock for PausedClock {
+    fn now(&self) -> Instant {
+        let state = self.state.lock().unwrap();
+        state.base + state.elapsed
+    }
+
+    fn schedule(&self, after: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = state.elapsed;
+        state.deadline = Some(elapsed + after);
+        state.interval = None;
+    }
+
+    fn schedule_interval(&self, period: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = state.elapsed;
+        state.deadline = Some(elapsed + period);
+        state.interval = Some(period);
+    }
+
+    fn cancel(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.deadline = None;
+        state.interval = None;
+    }
+
+    fn wake(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.terminated = true;
+        self.condvar.notify_all();
+    }
+
+    fn wait(&self) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.pending_fires > 0 {
+                state.pending_fires -= 1;
+                return Ok(());
+            }
+            if state.terminated {
+                return Err(std::io::Error::new(std::io::ErrorKind::Other, "clock terminated"));
+            }
+            state = self.condvar.wait(state).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn advance_fires_exactly_once_per_deadline() {
+        let clock = PausedClock::new();
+        clock.schedule(Duration::from_millis(100));
+
+        // Several small advances that don't cross the deadline: no fire.
+        for _ in 0..5 {
+            clock.advance(Duration::from_millis(10));
+        }
+        assert_eq!(clock.state.lock().unwrap().pending_fires, 0);
+
+        // The crossing advance: exactly one fire recorded, even though we
+        // jumped well past the deadline.
+        clock.advance(Duration::from_millis(100));
+        {
+            let mut state = clock.state.lock().unwrap();
+            assert_eq!(state.pending_fires, 1);
+            state.pending_fires = 0;
+        }
+
+        clock.wake();
+        assert!(clock.wait().is_err());
+    }
+
+    #[test]
+    fn interval_refires_on_each_crossed_period() {
+        let clock = PausedClock::new();
+        clock.schedule_interval(Duration::from_millis(10));
+
+        // Jumping forward by 35ms should cross 3 periods, but still only
+        // ever leaves one pending fire per `wait()` call -- the detector
+        // drains them one at a time, never in a burst.
+        clock.advance(Duration::from_millis(35));
+        let mut fires = 0;
+        while clock.state.lock().unwrap().pending_fires > 0 {
+            clock.wait().unwrap();
+            fires += 1;
+        }
+        assert_eq!(fires, 3);
+    }
+}
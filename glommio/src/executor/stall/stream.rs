@@ -0,0 +1,167 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2022 Datadog, Inc.
+//
+
+use crate::executor::{
+    stall::{default_high_water_mark, StallDetection, StallDetectionHandler},
+    TaskQueueHandle,
+};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Owned, `'static` form of [`StallDetection`], built once the borrowed
+/// queue name has been cloned and the backtraces already resolved, so the
+/// consumer side of [`StreamStallDetectionHandler`] never touches
+/// symbolization on its own time.
+#[derive(Debug, Clone)]
+pub struct OwnedStallDetection {
+    pub executor: usize,
+    pub queue_handle: TaskQueueHandle,
+    pub queue_name: String,
+    pub samples: Vec<(backtrace::Backtrace, usize)>,
+    pub sample_count: usize,
+    pub budget: Duration,
+    pub overage: Duration,
+}
+
+impl From<StallDetection<'_>> for OwnedStallDetection {
+    fn from(detection: StallDetection<'_>) -> Self {
+        Self {
+            executor: detection.executor,
+            queue_handle: detection.queue_handle,
+            queue_name: detection.queue_name.to_owned(),
+            samples: detection.samples,
+            sample_count: detection.sample_count,
+            budget: detection.budget,
+            overage: detection.overage,
+        }
+    }
+}
+
+/// A [`StallDetectionHandler`] that publishes every [`StallDetection`] onto
+/// a bounded channel, drained as a `Stream` the application can `.await` or
+/// `select!` over from within any task queue -- instead of every consumer
+/// reimplementing the handler just to export stalls to metrics, a
+/// dashboard, or structured tracing.
+///
+/// `stall()` runs on the executor thread from the guard's `Drop`, so
+/// publishing is a non-blocking `try_send`: a slow or absent consumer drops
+/// (and counts, see [`Self::dropped`]) overflow events rather than
+/// re-stalling the very queue being monitored.
+#[derive(Debug)]
+pub struct StreamStallDetectionHandler {
+    sender: Mutex<Sender<OwnedStallDetection>>,
+    dropped: AtomicUsize,
+    signal: u8,
+}
+
+impl StreamStallDetectionHandler {
+    /// Create a handler/consumer pair. Up to `capacity + 1` undelivered
+    /// stall events are buffered (the channel reserves one extra slot for
+    /// the handler's single sender) before new ones are dropped.
+    pub fn new(capacity: usize) -> (Self, Receiver<OwnedStallDetection>) {
+        let (sender, receiver) = channel(capacity);
+        (
+            Self {
+                sender: Mutex::new(sender),
+                dropped: AtomicUsize::new(0),
+                signal: nix::libc::SIGUSR1 as u8,
+            },
+            receiver,
+        )
+    }
+
+    /// Number of stall events dropped so far because the consumer `Stream`
+    /// wasn't being polled quickly enough (or at all) to keep up.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl StallDetectionHandler for StreamStallDetectionHandler {
+    fn high_water_mark(&self, _queue_handle: TaskQueueHandle, max_expected_runtime: Duration) -> Option<Duration> {
+        Some(default_high_water_mark(max_expected_runtime))
+    }
+
+    fn signal(&self) -> u8 {
+        self.signal
+    }
+
+    fn stall(&self, detection: StallDetection<'_>) {
+        let owned = OwnedStallDetection::from(detection);
+        let mut sender = self.sender.lock().unwrap();
+        if sender.try_send(owned).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Display for OwnedStallDetection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[stall-detector -- executor {}] task queue {} went over-budget: {:#?} (budget: \
+             {:#?}). {} sample(s) across {} distinct call stack(s)",
+            self.executor,
+            self.queue_name,
+            self.overage,
+            self.budget,
+            self.sample_count,
+            self.samples.len(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::executor::TaskQueueHandle;
+    use futures::StreamExt;
+
+    fn detection(queue_name: &str) -> StallDetection<'_> {
+        StallDetection {
+            executor: 0,
+            queue_handle: TaskQueueHandle::new(0),
+            queue_name,
+            samples: vec![],
+            sample_count: 0,
+            budget: Duration::from_millis(10),
+            overage: Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn stall_is_delivered_to_the_stream() {
+        let (handler, mut receiver) = StreamStallDetectionHandler::new(4);
+
+        handler.stall(detection("q"));
+
+        let received = futures::executor::block_on(receiver.next()).unwrap();
+        assert_eq!(received.queue_name, "q");
+        assert_eq!(received.overage, Duration::from_millis(5));
+        assert_eq!(handler.dropped(), 0);
+    }
+
+    #[test]
+    fn overflow_is_dropped_and_counted() {
+        // No spare buffer beyond the sender's own guaranteed slot, so the
+        // second `stall()` call is guaranteed to find the channel full.
+        let (handler, mut receiver) = StreamStallDetectionHandler::new(0);
+
+        handler.stall(detection("first"));
+        handler.stall(detection("second")); // channel is full: dropped
+        assert_eq!(handler.dropped(), 1);
+
+        let received = futures::executor::block_on(receiver.next()).unwrap();
+        assert_eq!(received.queue_name, "first");
+    }
+}
@@ -0,0 +1,135 @@
+// Unless explicitly stated otherwise all files in this repository are licensed
+// under the MIT/Apache-2.0 License, at your convenience
+//
+// This product includes software developed at Datadog (https://www.datadoghq.com/). Copyright 2022 Datadog, Inc.
+//
+
+use crate::timer::sleep;
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// The error returned by [`timeout`] (and [`Deadline`]) when the deadline
+/// elapses before the wrapped future completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedOut;
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "future timed out")
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Bound `fut` by `duration`: whichever of `fut` or the deadline resolves
+/// first wins. Equivalent to `Deadline::after(duration, fut)`.
+///
+/// ```ignore
+/// match glommio::timer::timeout(Duration::from_millis(100), some_io()).await {
+///     Ok(result) => ...,
+///     Err(TimedOut) => ...,
+/// }
+/// ```
+pub fn timeout<F, T>(duration: Duration, fut: F) -> Deadline<F>
+where
+    F: Future<Output = T>,
+{
+    Deadline::after(duration, fut)
+}
+
+/// A future that races an inner future against a reactor timer, yielding
+/// `Ok(T)` if the inner future completes first, or `Err(TimedOut)` once the
+/// deadline elapses. The timer is registered with the local reactor (the
+/// same `CLOCK_MONOTONIC` machinery [`sleep`] and the stall detector use),
+/// so waiting for the deadline costs no polling -- and the timer is
+/// automatically disarmed, via the embedded sleep future's own `Drop`, when
+/// the `Deadline` itself is dropped (which `.await` does as soon as either
+/// side wins the race).
+pub struct Deadline<F> {
+    fut: F,
+    timer: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl<F> Deadline<F> {
+    /// Race `fut` against the absolute deadline `at`.
+    pub fn new(at: Instant, fut: F) -> Self {
+        let timer = sleep(at.saturating_duration_since(Instant::now()));
+        Self {
+            fut,
+            timer: Box::pin(timer),
+        }
+    }
+
+    /// Race `fut` against a deadline `duration` from now.
+    pub fn after(duration: Duration, fut: F) -> Self {
+        Self::new(Instant::now() + duration, fut)
+    }
+}
+
+impl<F: Future> Future for Deadline<F> {
+    type Output = Result<F::Output, TimedOut>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `fut` is never moved out of `self` for as long as the
+        // `Deadline` exists, so projecting a pinned reference to it is
+        // sound even though `Deadline` doesn't implement `Unpin`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let fut = unsafe { Pin::new_unchecked(&mut this.fut) };
+
+        if let Poll::Ready(output) = fut.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match this.timer.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(TimedOut)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{timer::sleep, LocalExecutorBuilder};
+
+    #[test]
+    fn inner_future_wins_the_race() {
+        LocalExecutorBuilder::default()
+            .make()
+            .unwrap()
+            .run(async {
+                let result = timeout(Duration::from_millis(100), async { 42 }).await;
+                assert_eq!(result, Ok(42));
+            });
+    }
+
+    #[test]
+    fn deadline_elapses_first() {
+        LocalExecutorBuilder::default()
+            .make()
+            .unwrap()
+            .run(async {
+                let result = timeout(Duration::from_millis(10), sleep(Duration::from_millis(100))).await;
+                assert_eq!(result, Err(TimedOut));
+            });
+    }
+
+    #[test]
+    fn dropping_early_does_not_panic() {
+        LocalExecutorBuilder::default()
+            .make()
+            .unwrap()
+            .run(async {
+                // The inner future wins immediately; the still-armed sleep
+                // timer embedded in `Deadline` is cancelled on drop instead
+                // of firing later into a dead task.
+                let deadline = Deadline::after(Duration::from_secs(60), async { "done" });
+                assert_eq!(deadline.await, Ok("done"));
+            });
+    }
+}